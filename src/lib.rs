@@ -4,7 +4,9 @@
 //! This package was created for people who absolutely hate writing Javascript.
 //! The goal of this library is to reduce the amount of overhead required to implement
 //! WASM by automatically compiling WASM modules and statically linking them to
-//! your binary. You will need to have [wasm-bindgen](https://developer.mozilla.org/en-US/docs/WebAssembly/Rust_to_wasm) installed.
+//! your binary. The build runs `cargo` directly and then drives
+//! `wasm-bindgen-cli-support` in-process, so there's no separate `wasm-pack`
+//! binary to install.
 //! If your project stops building, please submit an issue. You may also try deleting .wasmir directory.
 
 //! # Usage
@@ -52,6 +54,31 @@
 //! features = ["Document", "Node", "Element"]
 //! )]
 //! ```
+//! By default the module is built with wasm-bindgen's `web` target. Pick a different
+//! output flavour with a top-level `target` key: `web`, `bundler`, `no-modules`,
+//! `nodejs`, `deno`, or `experimental-nodejs-module`.
+//! ```toml
+//! #[wasmir(
+//! target = "nodejs"
+//! )]
+//! ```
+//! Attach extra JS to the generated loader with `js` (inline) and/or
+//! `js_files` (paths relative to the crate root). It's exposed both merged
+//! into `loader` and standalone as `snippets`.
+//! ```toml
+//! #[wasmir(
+//! js = "export function helper() {}"
+//! js_files = ["src/polyfill.js"]
+//! )]
+//! ```
+//! Shrink the embedded binary with a `wasm-opt` pass via `opt` (a level
+//! `"0"`-`"4"`, `"s"`, or `"z"`, or `false` to skip it, the default). Requires
+//! `wasm-opt` on `PATH`; the stage is skipped with a warning if it's missing.
+//! ```toml
+//! #[wasmir(
+//! opt = "z"
+//! )]
+//! ```
 
 // Macro gets applied to module, function, struct, etc.
 // Macro calls compiler with web assembly target on code.
@@ -66,6 +93,190 @@ use std::io::prelude::*;
 use std::process::Command;
 use toml;
 use toml::Value;
+use wasm_bindgen_cli_support::Bindgen;
+
+/// The wasm-bindgen output flavour to generate, chosen via the `target = "..."`
+/// attribute key. Mirrors wasm-pack's own `--target` flag, plus the newer
+/// node ES-module target that wasm-pack itself doesn't know about (this one
+/// only builds because the build drives `wasm-bindgen-cli-support` directly;
+/// it isn't a real wasm-pack `--target` value).
+#[derive(Clone, Copy)]
+enum WasmTarget {
+	Web,
+	Bundler,
+	NoModules,
+	Nodejs,
+	Deno,
+	ExperimentalNodejsModule,
+}
+
+impl WasmTarget {
+	fn parse(value: &str) -> Result<WasmTarget, String> {
+		match value {
+			"web" => Ok(WasmTarget::Web),
+			"bundler" => Ok(WasmTarget::Bundler),
+			"no-modules" => Ok(WasmTarget::NoModules),
+			"nodejs" => Ok(WasmTarget::Nodejs),
+			"deno" => Ok(WasmTarget::Deno),
+			"experimental-nodejs-module" => Ok(WasmTarget::ExperimentalNodejsModule),
+			other => Err(format![
+				"unsupported wasmir target \"{}\"; expected one of web, bundler, no-modules, nodejs, deno, experimental-nodejs-module",
+				other
+			]),
+		}
+	}
+
+	fn name(&self) -> &'static str {
+		match self {
+			WasmTarget::Web => "web",
+			WasmTarget::Bundler => "bundler",
+			WasmTarget::NoModules => "no-modules",
+			WasmTarget::Nodejs => "nodejs",
+			WasmTarget::Deno => "deno",
+			WasmTarget::ExperimentalNodejsModule => "experimental-nodejs-module",
+		}
+	}
+
+	/// Wires this target into a `Bindgen` builder the way wasm-pack would pick
+	/// its own `--target` flag.
+	fn configure(&self, bindgen: &mut Bindgen) {
+		match self {
+			WasmTarget::Web => {
+				bindgen.web(true).expect("bindgen rejected web target");
+			}
+			WasmTarget::Bundler => {
+				bindgen.bundler(true).expect("bindgen rejected bundler target");
+			}
+			WasmTarget::NoModules => {
+				bindgen
+					.no_modules(true)
+					.expect("bindgen rejected no-modules target");
+			}
+			WasmTarget::Nodejs => {
+				bindgen.nodejs(true).expect("bindgen rejected nodejs target");
+			}
+			WasmTarget::Deno => {
+				bindgen.deno(true).expect("bindgen rejected deno target");
+			}
+			WasmTarget::ExperimentalNodejsModule => {
+				bindgen
+					.nodejs_module(true)
+					.expect("bindgen rejected experimental-nodejs-module target");
+			}
+		}
+	}
+}
+
+/// Turns a build/bindgen failure into a real `compile_error!` in the caller's
+/// token stream instead of panicking the whole proc-macro process.
+fn compile_error(message: &str) -> TokenStream {
+	let message = message.to_string();
+	quote![compile_error!(#message);].into()
+}
+
+/// Parses a cargo `.d` dep-info file into the list of files the build depends
+/// on. Handles the two quirks cargo's dep-info emits: a rule split across
+/// multiple lines with a trailing `\` continuation, and literal spaces in
+/// paths escaped as `\ `.
+fn parse_dep_info(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+	let text = match std::fs::read_to_string(path) {
+		Ok(text) => text,
+		Err(_) => return vec![],
+	};
+
+	let mut joined = String::new();
+	for line in text.lines() {
+		match line.strip_suffix('\\') {
+			Some(stripped) => joined.push_str(stripped),
+			None => {
+				joined.push_str(line);
+				joined.push('\n');
+			}
+		}
+	}
+
+	let mut deps = vec![];
+	for rule in joined.lines() {
+		let rest = match rule.split_once(':') {
+			Some((_target, rest)) => rest,
+			None => continue,
+		};
+
+		let mut current = String::new();
+		let mut chars = rest.chars().peekable();
+		while let Some(c) = chars.next() {
+			if c == '\\' && chars.peek() == Some(&' ') {
+				current.push(' ');
+				chars.next();
+			} else if c.is_whitespace() {
+				if !current.is_empty() {
+					deps.push(std::path::PathBuf::from(current.clone()));
+					current.clear();
+				}
+			} else {
+				current.push(c);
+			}
+		}
+		if !current.is_empty() {
+			deps.push(std::path::PathBuf::from(current));
+		}
+	}
+	deps
+}
+
+/// Rewrites every `path = "..."` dependency key (including `[dependencies.*]`
+/// tables, which toml parses into the same shape as an inline table) so it's
+/// anchored at `anchor` instead of wherever the module crate happens to live.
+/// This is what makes `wasm-bindgen = { path = "../wasm-bindgen" }` and local
+/// workspace crates usable from inside a `#[wasmir]` module.
+fn resolve_dependency_paths(deps: &mut toml::map::Map<String, Value>, anchor: &std::path::Path) {
+	for (_name, dep) in deps.iter_mut() {
+		if let Value::Table(dep_table) = dep {
+			if let Some(Value::String(path)) = dep_table.get("path") {
+				let resolved = anchor.join(path);
+				dep_table.insert(
+					"path".to_string(),
+					Value::String(resolved.to_string_lossy().to_string()),
+				);
+			}
+		}
+	}
+}
+
+/// Hashes everything that should force a rebuild if it changes: the module's
+/// own source, its resolved dependency TOML, the chosen target, the chosen
+/// wasm-opt level, the active toolchain, and the contents of every file
+/// cargo's dep-info says the crate depends on (picking up edits to
+/// path/workspace dependencies). Dep files are hashed by content rather than
+/// mtime: the generated `lib.rs`/`Cargo.toml` are always in that list, and we
+/// rewrite both on every expansion regardless of whether their bytes
+/// changed, so an mtime-based hash would never let the cache hit.
+fn compute_fingerprint(
+	module_text: &str,
+	resolved_cargo_toml: &str,
+	target: &str,
+	opt_level: &str,
+	toolchain: &str,
+	dep_files: &[std::path::PathBuf],
+) -> String {
+	use std::hash::{Hash, Hasher};
+
+	let mut hasher = std::collections::hash_map::DefaultHasher::new();
+	module_text.hash(&mut hasher);
+	resolved_cargo_toml.hash(&mut hasher);
+	target.hash(&mut hasher);
+	opt_level.hash(&mut hasher);
+	toolchain.hash(&mut hasher);
+
+	for dep in dep_files {
+		dep.to_string_lossy().hash(&mut hasher);
+		if let Ok(contents) = std::fs::read(dep) {
+			contents.hash(&mut hasher);
+		}
+	}
+
+	format!["{:016x}", hasher.finish()]
+}
 
 fn token_tree_to_toml(tree: TokenTree, prev: &Option<TokenTree>) -> String {
 	let mut buf = String::new();
@@ -151,7 +362,67 @@ pub fn wasmir(attr: TokenStream, input: TokenStream) -> TokenStream {
 
 	let attr = TokenStream2::from(attr);
 	let dependencies = token_stream_to_toml(attr);
-	println!["{}", dependencies];
+
+	let attr_toml: Value = toml::from_str(&dependencies).expect("failed to parse dependencies toml");
+
+	// `target = "..."` picks which wasm-bindgen output flavour to generate.
+	let wasm_target = match attr_toml.get("target") {
+		Some(Value::String(target)) => match WasmTarget::parse(target) {
+			Ok(target) => target,
+			Err(message) => return compile_error(&message),
+		},
+		_ => WasmTarget::Web,
+	};
+
+	// `js = "..."` is concatenated in verbatim, and each entry in
+	// `js_files = [...]` is read from a path relative to `CARGO_MANIFEST_DIR`,
+	// so users can ship helper functions/polyfills alongside the generated
+	// bindings without maintaining a separate asset pipeline.
+	let mut js_snippet_parts: Vec<String> = vec![];
+	if let Some(Value::Array(files)) = attr_toml.get("js_files") {
+		for file in files {
+			let Value::String(relative_path) = file else {
+				return compile_error("wasmir: js_files entries must be strings");
+			};
+			let snippet_path = project_root.join(relative_path);
+			if !snippet_path.exists() {
+				return compile_error(&format![
+					"wasmir: js_files entry \"{}\" does not exist relative to CARGO_MANIFEST_DIR",
+					relative_path
+				]);
+			}
+			match std::fs::read_to_string(&snippet_path) {
+				Ok(text) => js_snippet_parts.push(text),
+				Err(e) => {
+					return compile_error(&format![
+						"wasmir: could not read js snippet \"{}\": {}",
+						relative_path, e
+					])
+				}
+			}
+		}
+	}
+	if let Some(Value::String(inline_js)) = attr_toml.get("js") {
+		js_snippet_parts.push(inline_js.clone());
+	}
+	let js_snippets = js_snippet_parts.join("\n");
+
+	// `opt = "z"` / `opt = "3"` runs `wasm-opt` over the bindgen'd artifact
+	// before it's embedded; `opt = false` (the default) skips the stage.
+	let opt_level: Option<String> = match attr_toml.get("opt") {
+		Some(Value::String(level)) => {
+			let valid_levels = ["0", "1", "2", "3", "4", "s", "z"];
+			if !valid_levels.contains(&level.as_str()) {
+				return compile_error(&format![
+					"wasmir: unsupported wasm-opt level \"{}\"; expected one of 0, 1, 2, 3, 4, s, z",
+					level
+				]);
+			}
+			Some(level.clone())
+		}
+		Some(Value::Boolean(false)) | None => None,
+		Some(_) => return compile_error("wasmir: `opt` must be a wasm-opt level string or `false`"),
+	};
 
 	let input = TokenStream2::from(input);
 	let mut module_name = String::new();
@@ -251,10 +522,13 @@ pub fn wasmir(attr: TokenStream, input: TokenStream) -> TokenStream {
 		}
 	}
 
-	let mut dependencies_toml: Value =
-		toml::from_str(&dependencies).expect("failed to parse dependencies toml");
+	let mut dependencies_toml: Value = attr_toml.clone();
 	match dependencies_toml.get_mut("dependencies") {
 		Some(Value::Table(deps)) => {
+			// The module crate lives under `.wasmir/<module>`, not
+			// `CARGO_MANIFEST_DIR`, so any `path = "..."` a user wrote relative to
+			// their own crate would otherwise resolve against the wrong directory.
+			resolve_dependency_paths(deps, &project_root);
 			if let Some(Value::Table(lib_deps)) = cargo_toml.get_mut("dependencies") {
 				lib_deps.extend(deps.iter().map(|(k, v)| (k.clone(), v.clone())));
 			}
@@ -267,58 +541,199 @@ pub fn wasmir(attr: TokenStream, input: TokenStream) -> TokenStream {
 	file.write(&format!["{}", cargo_toml].bytes().collect::<Vec<u8>>())
 		.expect("failed to write to Cargo.toml");
 
-	// Build the module using `wasm-pack build --target web`
-	env::set_current_dir(module_root.clone()).expect("could not set current directory");
-	match Command::new("wasm-pack")
-		.arg("build")
-		.arg("--target")
-		.arg("web")
-		.output()
-	{
-		Ok(o) => {
-			println!["{}", String::from_utf8(o.stderr).unwrap()];
-		}
-		Err(e) => {
-			panic!["could not build: {}", e];
-		}
-	}
+	let pkg_dir = module_root.join("pkg");
+	create_dir_all(&pkg_dir).expect("couldn't create pkg output directory");
+
+	let dep_info_path = module_root
+		.join("target")
+		.join("wasm32-unknown-unknown")
+		.join("release")
+		.join(format!["{}.d", module_name]);
+	let fingerprint_path = module_root.join(".wasmir-fingerprint");
+	let resolved_cargo_toml = format!["{}", cargo_toml];
+	let toolchain = match Command::new("rustc").arg("--version").output() {
+		Ok(o) => String::from_utf8_lossy(&o.stdout).trim().to_string(),
+		Err(_) => "unknown rustc".to_string(),
+	};
 
-	let mut file = match File::open(
-		module_root
-			.join("pkg")
-			.join(format!["{}_bg.wasm", module_name.clone()]),
-	) {
-		Ok(file) => file,
-		Err(e) => panic!["could not open binary: {}", e],
+	// Whether wasm-opt actually runs depends on both the requested level and
+	// whether the binary is on PATH, so fold both into the fingerprint. That
+	// way installing/removing wasm-opt forces a rebuild even if nothing else
+	// about the module changed.
+	let wasm_opt_available = Command::new("wasm-opt").arg("--version").output().is_ok();
+	let opt_fingerprint = match &opt_level {
+		Some(level) if wasm_opt_available => format!["applied:{}", level],
+		Some(level) => format!["skipped:{}", level],
+		None => "none".to_string(),
 	};
 
-	let mut binary = vec![];
+	let artifacts_exist = pkg_dir.join(format!["{}_bg.wasm", module_name]).exists()
+		&& pkg_dir.join(format!["{}.js", module_name]).exists();
+	let fingerprint_before_build = compute_fingerprint(
+		&module_text,
+		&resolved_cargo_toml,
+		wasm_target.name(),
+		&opt_fingerprint,
+		&toolchain,
+		&parse_dep_info(&dep_info_path),
+	);
+	let cache_hit = artifacts_exist
+		&& std::fs::read_to_string(&fingerprint_path)
+			.map(|stored| stored == fingerprint_before_build)
+			.unwrap_or(false);
+
+	if !cache_hit {
+		// Compile the module crate to a raw wasm32-unknown-unknown artifact ourselves,
+		// instead of shelling out to wasm-pack, so we can surface failures as
+		// `compile_error!` and drive wasm-bindgen directly below.
+		env::set_current_dir(module_root.clone()).expect("could not set current directory");
+		match Command::new("cargo")
+			.arg("build")
+			.arg("--target")
+			.arg("wasm32-unknown-unknown")
+			.arg("--release")
+			.output()
+		{
+			Ok(o) if o.status.success() => {
+				println!["{}", String::from_utf8_lossy(&o.stderr)];
+			}
+			Ok(o) => {
+				return compile_error(&format![
+					"wasmir: `cargo build` failed for module `{}`:\n{}",
+					module_name,
+					String::from_utf8_lossy(&o.stderr)
+				]);
+			}
+			Err(e) => {
+				return compile_error(&format!["wasmir: could not run cargo: {}", e]);
+			}
+		}
+
+		let input_wasm = module_root
+			.join("target")
+			.join("wasm32-unknown-unknown")
+			.join("release")
+			.join(format!["{}.wasm", module_name]);
+
+		let mut bindgen = Bindgen::new();
+		bindgen.input_path(&input_wasm).out_name(&module_name);
+		wasm_target.configure(&mut bindgen);
+
+		if let Err(e) = bindgen.generate(&pkg_dir) {
+			return compile_error(&format![
+				"wasmir: wasm-bindgen failed for module `{}`: {}",
+				module_name, e
+			]);
+		}
 
-	file.read_to_end(&mut binary)
-		.expect("could not read-in binary");
+		if let Some(level) = &opt_level {
+			if !wasm_opt_available {
+				println![
+					"wasmir: opt = \"{}\" requested but wasm-opt was not found on PATH; skipping",
+					level
+				];
+			} else {
+				let bindgen_wasm = pkg_dir.join(format!["{}_bg.wasm", module_name]);
+				let optimized_wasm = pkg_dir.join(format!["{}_bg.opt.wasm", module_name]);
+				match Command::new("wasm-opt")
+					.arg(format!["-O{}", level])
+					.arg(&bindgen_wasm)
+					.arg("-o")
+					.arg(&optimized_wasm)
+					.output()
+				{
+					Ok(o) if o.status.success() => {
+						std::fs::rename(&optimized_wasm, &bindgen_wasm)
+							.expect("could not replace wasm with wasm-opt output");
+					}
+					Ok(o) => {
+						return compile_error(&format![
+							"wasmir: wasm-opt failed for module `{}`:\n{}",
+							module_name,
+							String::from_utf8_lossy(&o.stderr)
+						]);
+					}
+					Err(e) => {
+						return compile_error(&format!["wasmir: could not run wasm-opt: {}", e]);
+					}
+				}
+			}
+		}
 
-	let binary_len = binary.len();
+		// Only fingerprint a build that actually succeeded, so an interrupted
+		// or failed build doesn't poison the cache for next time.
+		let fingerprint_after_build = compute_fingerprint(
+			&module_text,
+			&resolved_cargo_toml,
+			wasm_target.name(),
+			&opt_fingerprint,
+			&toolchain,
+			&parse_dep_info(&dep_info_path),
+		);
+		std::fs::write(&fingerprint_path, &fingerprint_after_build)
+			.expect("failed to write wasmir fingerprint");
+	}
 
-	let mut file = match File::open(
-		module_root
-			.join("pkg")
-			.join(format!["{}.js", module_name.clone()]),
-	) {
-		Ok(file) => file,
-		Err(e) => panic!["could not open js: {}", e],
+	// Embed the artifacts by copying them to a stable path and referring to that
+	// path with `include_bytes!`/`include_str!`, rather than unrolling the wasm
+	// binary into a token per byte. A realistic module is tens to hundreds of
+	// kilobytes, which as a literal array token stream makes rustc's job to
+	// parse and type-check the expansion far slower than it needs to be.
+	let out_dir = env::var("OUT_DIR")
+		.map(std::path::PathBuf::from)
+		.unwrap_or_else(|_| wasmir_dir.clone());
+	let embed_dir = out_dir.join(".wasmir").join(&module_name);
+	create_dir_all(&embed_dir).expect("couldn't create wasmir embed directory");
+
+	let embedded_wasm_path = embed_dir.join(format!["{}_bg.wasm", module_name]);
+	std::fs::copy(
+		module_root.join("pkg").join(format!["{}_bg.wasm", module_name]),
+		&embedded_wasm_path,
+	)
+	.expect("could not copy wasm binary for embedding");
+
+	// The generated loader gets any attached JS snippets appended, so `loader`
+	// stays the single string users feed to their bundler/`<script>` tag; the
+	// snippets are also kept standalone under `snippets` for anyone who wants
+	// them separately from wasm-bindgen's own glue.
+	let generated_js = std::fs::read_to_string(
+		module_root.join("pkg").join(format!["{}.js", module_name]),
+	)
+	.expect("could not read generated js loader");
+	let merged_js = if js_snippets.is_empty() {
+		generated_js
+	} else {
+		format!["{}\n{}", generated_js, js_snippets]
 	};
 
-	let mut js = String::new();
+	let embedded_js_path = embed_dir.join(format!["{}.js", module_name]);
+	std::fs::write(&embedded_js_path, &merged_js).expect("could not write js loader for embedding");
+
+	let embedded_snippets_path = embed_dir.join(format!["{}_snippets.js", module_name]);
+	std::fs::write(&embedded_snippets_path, &js_snippets)
+		.expect("could not write js snippets for embedding");
 
-	file.read_to_string(&mut js).expect("could not read-in js");
+	let wasm_path = embedded_wasm_path
+		.to_str()
+		.expect("wasmir embed path must be valid UTF-8")
+		.to_string();
+	let js_path = embedded_js_path
+		.to_str()
+		.expect("wasmir embed path must be valid UTF-8")
+		.to_string();
+	let snippets_path = embedded_snippets_path
+		.to_str()
+		.expect("wasmir embed path must be valid UTF-8")
+		.to_string();
 
-	let module_name = Ident::new(module_name.as_str(), Span::call_site());
+	let module_ident = Ident::new(module_name.as_str(), Span::call_site());
 
 	quote![
-	 mod #module_name {
+	 mod #module_ident {
 	   #input
-		pub const wasm: [u8; #binary_len] = [#(#binary),*];
-		pub const loader: &str = #js;
+		pub static wasm: &[u8] = include_bytes!(#wasm_path);
+		pub static loader: &str = include_str!(#js_path);
+		pub static snippets: &str = include_str!(#snippets_path);
 	}]
 	.into()
 }